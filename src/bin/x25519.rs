@@ -3,11 +3,11 @@ use rand::rngs::OsRng;
 
 fn main() {
     // Alice generates her key pair
-    let alice_secret: EphemeralSecret = EphemeralSecret::random_from_rng(&mut OsRng);
+    let alice_secret: EphemeralSecret = EphemeralSecret::random_from_rng(OsRng);
     let alice_public: PublicKey = PublicKey::from(&alice_secret);
 
     // Bob generates his key pair
-    let bob_secret: EphemeralSecret = EphemeralSecret::random_from_rng(&mut OsRng);
+    let bob_secret: EphemeralSecret = EphemeralSecret::random_from_rng(OsRng);
     let bob_public: PublicKey = PublicKey::from(&bob_secret);
 
     // Alice and Bob exchange public keys and compute the shared secret
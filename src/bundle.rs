@@ -0,0 +1,163 @@
+use std::fmt;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::PublicKey;
+
+use crate::user::HandshakeError;
+
+// size of the fixed part of the binary encoding:
+// device registration id (4) | ik (32) | vk (32) | spk (32) | spk signature (64) | opk count (2)
+// followed by opk count entries of id (4) | opk (32)
+const HEADER_LEN: usize = 4 + 32 + 32 + 32 + 64 + 2;
+const OPK_LEN: usize = 4 + 32;
+
+// A user's published keys, sent to peers and stored per peer in User::key_bundles (also named UserBundle)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyBundle {
+    pub device_registration_id: u32,
+    pub ik_p: PublicKey, //public identity key
+    pub vk_p: VerifyingKey, //public identity signing key
+    pub spk_p: PublicKey, //public signed pre key
+    pub spk_sig: Signature, //signature over spk_p
+    pub opks_p: Vec<(u32, PublicKey)> //published one-time pre keys with their ids
+}
+
+// Reasons a byte string is not a valid KeyBundle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleError {
+    TooShort, //input ends before all fields were read
+    TrailingBytes, //input continues after the last one-time pre key
+    TooManyOpks, //more one-time pre keys than the u16 count can carry
+    InvalidVerifyingKey //vk bytes are not a valid Ed25519 point
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::TooShort => write!(f, "key bundle is truncated"),
+            BundleError::TrailingBytes => write!(f, "key bundle has trailing bytes"),
+            BundleError::TooManyOpks => write!(f, "key bundle has too many one-time pre keys"),
+            BundleError::InvalidVerifyingKey => write!(f, "key bundle has an invalid signing key"),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+impl KeyBundle {
+    // Check the signed pre key signature against the publisher's Ed25519 verifying key
+    pub fn verify(&self) -> Result<(), HandshakeError> {
+        self.vk_p
+            .verify_strict(self.spk_p.as_bytes(), &self.spk_sig)
+            .map_err(|_| HandshakeError::InvalidSignature)
+    }
+
+    // Compact binary encoding, all integers big endian:
+    // device registration id u32 | ik | vk | spk | spk signature | opk count u16 | (opk id u32 | opk)*
+    pub fn serialize(&self) -> Result<Vec<u8>, BundleError> {
+        let opk_count: u16 = u16::try_from(self.opks_p.len()).map_err(|_| BundleError::TooManyOpks)?;
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(HEADER_LEN + OPK_LEN * self.opks_p.len());
+        bytes.extend_from_slice(&self.device_registration_id.to_be_bytes());
+        bytes.extend_from_slice(self.ik_p.as_bytes());
+        bytes.extend_from_slice(self.vk_p.as_bytes());
+        bytes.extend_from_slice(self.spk_p.as_bytes());
+        bytes.extend_from_slice(&self.spk_sig.to_bytes());
+        bytes.extend_from_slice(&opk_count.to_be_bytes());
+        for (id, opk_p) in &self.opks_p {
+            bytes.extend_from_slice(&id.to_be_bytes());
+            bytes.extend_from_slice(opk_p.as_bytes());
+        }
+        Ok(bytes)
+    }
+
+    // Parse the encoding produced by serialize, rejecting truncated or padded input
+    pub fn deserialize(bytes: &[u8]) -> Result<KeyBundle, BundleError> {
+        let mut reader = Reader { bytes };
+
+        let device_registration_id: u32 = u32::from_be_bytes(reader.take()?);
+        let ik_p: PublicKey = PublicKey::from(reader.take::<32>()?);
+        let vk_p: VerifyingKey = VerifyingKey::from_bytes(&reader.take()?)
+            .map_err(|_| BundleError::InvalidVerifyingKey)?;
+        let spk_p: PublicKey = PublicKey::from(reader.take::<32>()?);
+        let spk_sig: Signature = Signature::from_bytes(&reader.take()?);
+        let opk_count: u16 = u16::from_be_bytes(reader.take()?);
+        // check the count against the input before allocating for it
+        if reader.bytes.len() < opk_count as usize * OPK_LEN {
+            return Err(BundleError::TooShort);
+        }
+
+        let mut opks_p: Vec<(u32, PublicKey)> = Vec::with_capacity(opk_count as usize);
+        for _ in 0..opk_count {
+            let id: u32 = u32::from_be_bytes(reader.take()?);
+            opks_p.push((id, PublicKey::from(reader.take::<32>()?)));
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(BundleError::TrailingBytes);
+        }
+
+        Ok(KeyBundle { device_registration_id, ik_p, vk_p, spk_p, spk_sig, opks_p })
+    }
+}
+
+// reads fixed size fields off the front of a byte slice
+struct Reader<'a> {
+    bytes: &'a [u8]
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], BundleError> {
+        if self.bytes.len() < N {
+            return Err(BundleError::TooShort);
+        }
+        let (field, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(field.try_into().expect("split_at returned N bytes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::User;
+
+    #[test]
+    fn binary_round_trip() {
+        let bundle = User::new("Alice".to_string(), 3).publish();
+        let bytes = bundle.serialize().unwrap();
+
+        assert_eq!(bytes.len(), HEADER_LEN + 3 * OPK_LEN);
+        assert_eq!(KeyBundle::deserialize(&bytes), Ok(bundle));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let bundle = User::new("Alice".to_string(), 2).publish();
+        let json = serde_json::to_string(&bundle).unwrap();
+
+        assert_eq!(serde_json::from_str::<KeyBundle>(&json).unwrap(), bundle);
+    }
+
+    #[test]
+    fn rejects_truncated_and_padded_input() {
+        let bundle = User::new("Alice".to_string(), 1).publish();
+        let mut bytes = bundle.serialize().unwrap();
+
+        assert_eq!(KeyBundle::deserialize(&bytes[..bytes.len() - 1]), Err(BundleError::TooShort));
+        assert_eq!(KeyBundle::deserialize(&bytes[..10]), Err(BundleError::TooShort));
+        bytes.push(0);
+        assert_eq!(KeyBundle::deserialize(&bytes), Err(BundleError::TrailingBytes));
+    }
+
+    #[test]
+    fn rejects_opk_count_larger_than_input() {
+        let bundle = User::new("Alice".to_string(), 1).publish();
+        let mut bytes = bundle.serialize().unwrap();
+
+        // claim the maximum count with only one pre key present
+        bytes[HEADER_LEN - 2..HEADER_LEN].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(KeyBundle::deserialize(&bytes), Err(BundleError::TooShort));
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+use aes::Aes256;
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::ratchet::MessageKeys;
+
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+// length of the HMAC-SHA256 tag appended to every ciphertext
+pub const MAC_LEN: usize = 32;
+
+// Reasons an authenticated ciphertext is rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AeadError {
+    InvalidMac, //the tag does not match the ciphertext and associated data
+    InvalidCiphertext //the tag matched but the ciphertext does not decrypt
+}
+
+impl fmt::Display for AeadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AeadError::InvalidMac => write!(f, "MAC verification failed"),
+            AeadError::InvalidCiphertext => write!(f, "ciphertext could not be decrypted"),
+        }
+    }
+}
+
+impl std::error::Error for AeadError {}
+
+// AES-256-CBC encrypt with the message keys, then append HMAC-SHA256 over the
+// associated data and the ciphertext (encrypt-then-MAC)
+pub fn encrypt_message(keys: &MessageKeys, plaintext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+    let mut ciphertext: Vec<u8> = Aes256CbcEnc::new(&keys.cipher_key.into(), &keys.iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+    let tag = mac(keys, associated_data, &ciphertext).finalize().into_bytes();
+    ciphertext.extend_from_slice(&tag);
+    ciphertext
+}
+
+// Check the tag in constant time, and only then decrypt
+pub fn decrypt_message(keys: &MessageKeys, ciphertext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, AeadError> {
+    if ciphertext.len() < MAC_LEN {
+        return Err(AeadError::InvalidMac);
+    }
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - MAC_LEN);
+    mac(keys, associated_data, ciphertext).verify_slice(tag).map_err(|_| AeadError::InvalidMac)?;
+
+    Aes256CbcDec::new(&keys.cipher_key.into(), &keys.iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| AeadError::InvalidCiphertext)
+}
+
+// the associated data is length prefixed so it can not be shifted into the ciphertext
+fn mac(keys: &MessageKeys, associated_data: &[u8], ciphertext: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(&keys.mac_key).expect("HMAC accepts any key length");
+    mac.update(&(associated_data.len() as u64).to_be_bytes());
+    mac.update(associated_data);
+    mac.update(ciphertext);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratchet::ChainKey;
+
+    fn keys(seed: u8) -> MessageKeys {
+        ChainKey::new([seed; 32], 0).message_keys()
+    }
+
+    #[test]
+    fn round_trip() {
+        let ciphertext = encrypt_message(&keys(1), b"plaintext", b"header");
+
+        // one padded block plus the tag
+        assert_eq!(ciphertext.len(), 16 + MAC_LEN);
+        assert_eq!(decrypt_message(&keys(1), &ciphertext, b"header").unwrap(), b"plaintext");
+    }
+
+    #[test]
+    fn rejects_wrong_keys_and_associated_data() {
+        let ciphertext = encrypt_message(&keys(1), b"plaintext", b"header");
+
+        assert_eq!(decrypt_message(&keys(2), &ciphertext, b"header"), Err(AeadError::InvalidMac));
+        assert_eq!(decrypt_message(&keys(1), &ciphertext, b"other header"), Err(AeadError::InvalidMac));
+        assert_eq!(decrypt_message(&keys(1), &ciphertext, b""), Err(AeadError::InvalidMac));
+    }
+
+    #[test]
+    fn rejects_tampered_and_truncated_ciphertext() {
+        let mut ciphertext = encrypt_message(&keys(1), b"plaintext", b"");
+
+        assert_eq!(decrypt_message(&keys(1), &ciphertext[..MAC_LEN - 1], b""), Err(AeadError::InvalidMac));
+        assert_eq!(decrypt_message(&keys(1), &ciphertext[1..], b""), Err(AeadError::InvalidMac));
+        ciphertext[0] ^= 1;
+        assert_eq!(decrypt_message(&keys(1), &ciphertext, b""), Err(AeadError::InvalidMac));
+    }
+}
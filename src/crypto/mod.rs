@@ -0,0 +1,2 @@
+// Symmetric primitives used by the ratchet
+pub mod aead;
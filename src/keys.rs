@@ -0,0 +1,24 @@
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+// An X25519 key pair. The private half is a StaticSecret so it can take part in
+// any number of DH computations (an identity key is used once per session).
+#[derive(Clone)]
+pub struct KeyPair {
+    pub private: StaticSecret,
+    pub public: PublicKey
+}
+
+impl KeyPair {
+    // Generate a fresh key pair from the OS CSPRNG
+    pub fn generate() -> KeyPair {
+        let private: StaticSecret = StaticSecret::random_from_rng(OsRng);
+        let public: PublicKey = PublicKey::from(&private); // Derives the public key from the private key
+        KeyPair { private, public }
+    }
+
+    // DH between our private key and their public key, does not consume the key pair
+    pub fn diffie_hellman(&self, their_public: &PublicKey) -> SharedSecret {
+        self.private.diffie_hellman(their_public)
+    }
+}
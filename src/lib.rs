@@ -0,0 +1,15 @@
+// Signal style end to end encryption: X3DH key agreement and Double Ratchet sessions between users
+pub mod bundle;
+pub mod crypto;
+pub mod keys;
+pub mod ratchet;
+pub mod server;
+pub mod session;
+pub mod user;
+
+pub use bundle::{BundleError, KeyBundle};
+pub use keys::KeyPair;
+pub use ratchet::{ChainKey, MessageKeys, RootKey};
+pub use server::{KeyServer, KeyServerError};
+pub use session::{RatchetMessage, Session, SessionError, MAX_SKIP, MAX_SKIPPED_KEYS};
+pub use user::{HandshakeError, InitialMessage, PeerRegistration, User, UserBundle};
@@ -0,0 +1,96 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::PublicKey;
+
+use crate::keys::KeyPair;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// info strings for the two HKDF uses in the ratchet
+const RATCHET_INFO: &[u8] = b"PQ_Signal_Ratchet";
+const MESSAGE_KEYS_INFO: &[u8] = b"PQ_Signal_MessageKeys";
+
+// HMAC inputs for stepping a chain: 0x01 gives the message key seed, 0x02 the next chain key
+const MESSAGE_KEY_SEED: u8 = 0x01;
+const CHAIN_KEY_SEED: u8 = 0x02;
+
+// Root of the Double Ratchet, mixed with a fresh DH output on every ratchet step
+#[derive(Clone)]
+pub struct RootKey {
+    key: [u8; 32]
+}
+
+impl RootKey {
+    // The first root key is the X3DH shared secret
+    pub fn new(key: [u8; 32]) -> RootKey {
+        RootKey { key }
+    }
+
+    // DH ratchet step: KDF(root, DH(ours, theirs)) gives the next root key and a new chain
+    pub fn create_chain(&self, their_ratchet_key: &PublicKey, our_ratchet_key: &KeyPair) -> (RootKey, ChainKey) {
+        let shared_secret = our_ratchet_key.diffie_hellman(their_ratchet_key);
+        let hkdf = Hkdf::<Sha256>::new(Some(&self.key), shared_secret.as_bytes());
+        let mut output = [0u8; 64];
+        hkdf.expand(RATCHET_INFO, &mut output).expect("HKDF expand error");
+
+        let mut root_key = [0u8; 32];
+        let mut chain_key = [0u8; 32];
+        root_key.copy_from_slice(&output[..32]);
+        chain_key.copy_from_slice(&output[32..]);
+        (RootKey::new(root_key), ChainKey::new(chain_key, 0))
+    }
+}
+
+// A sending or receiving chain, stepped once per message (symmetric ratchet)
+#[derive(Clone)]
+pub struct ChainKey {
+    key: [u8; 32],
+    index: u32
+}
+
+impl ChainKey {
+    pub fn new(key: [u8; 32], index: u32) -> ChainKey {
+        ChainKey { key, index }
+    }
+
+    // counter of the next message this chain will produce keys for
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn next_chain_key(&self) -> ChainKey {
+        ChainKey::new(self.calculate_base_material(CHAIN_KEY_SEED), self.index + 1)
+    }
+
+    // Keys for the message at this chain's index
+    pub fn message_keys(&self) -> MessageKeys {
+        let seed = self.calculate_base_material(MESSAGE_KEY_SEED);
+        let hkdf = Hkdf::<Sha256>::new(None, &seed);
+        let mut output = [0u8; 80];
+        hkdf.expand(MESSAGE_KEYS_INFO, &mut output).expect("HKDF expand error");
+
+        let mut cipher_key = [0u8; 32];
+        let mut mac_key = [0u8; 32];
+        let mut iv = [0u8; 16];
+        cipher_key.copy_from_slice(&output[..32]);
+        mac_key.copy_from_slice(&output[32..64]);
+        iv.copy_from_slice(&output[64..]);
+        MessageKeys { cipher_key, mac_key, iv, counter: self.index }
+    }
+
+    fn calculate_base_material(&self, seed: u8) -> [u8; 32] {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(&[seed]);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+// Single use keys for one message
+#[derive(Clone)]
+pub struct MessageKeys {
+    pub cipher_key: [u8; 32], //AES-256 key
+    pub mac_key: [u8; 32], //HMAC-SHA256 key
+    pub iv: [u8; 16], //AES-CBC iv
+    pub counter: u32 //index of the message in its chain
+}
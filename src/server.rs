@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use x25519_dalek::PublicKey;
+
+use crate::user::{HandshakeError, UserBundle};
+
+// Reasons the key server refuses a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyServerError {
+    UnknownUser, //no bundle was registered under that name
+    InvalidBundle(HandshakeError) //the bundle's signed pre key does not verify
+}
+
+impl fmt::Display for KeyServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyServerError::UnknownUser => write!(f, "unknown user"),
+            KeyServerError::InvalidBundle(err) => write!(f, "invalid bundle: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for KeyServerError {}
+
+// An in-process stand in for Signal's key distribution server.
+// Users publish their bundles here and initiators fetch them, each fetch hands out one one-time pre key at most.
+#[derive(Default)]
+pub struct KeyServer {
+    bundles: HashMap<String, UserBundle>
+}
+
+impl KeyServer {
+    pub fn new() -> KeyServer {
+        KeyServer { bundles: HashMap::new() }
+    }
+
+    // Publish a user's bundle, replacing any earlier one. Bundles that do not verify are refused.
+    // Registering again with the same identity key keeps the one-time pre keys the server still holds, so keys
+    // already handed out are not offered twice. A new identity key is a new installation and brings its own.
+    pub fn register(&mut self, user_name: &str, mut bundle: UserBundle) -> Result<(), KeyServerError> {
+        bundle.verify().map_err(KeyServerError::InvalidBundle)?;
+        if let Some(current) = self.bundles.get_mut(user_name) {
+            if current.ik_p == bundle.ik_p {
+                bundle.opks_p = std::mem::take(&mut current.opks_p);
+            }
+        }
+        self.bundles.insert(user_name.to_string(), bundle);
+        Ok(())
+    }
+
+    // Bundle for starting a handshake with user_name. The first one-time pre key is removed from the
+    // server and included, once they are exhausted the bundle comes without one.
+    pub fn fetch_bundle(&mut self, user_name: &str) -> Result<UserBundle, KeyServerError> {
+        let bundle = self.bundles.get_mut(user_name).ok_or(KeyServerError::UnknownUser)?;
+        let opks_p: Vec<(u32, PublicKey)> = if bundle.opks_p.is_empty() {
+            Vec::new()
+        } else {
+            vec![bundle.opks_p.remove(0)]
+        };
+
+        Ok(UserBundle { opks_p, ..bundle.clone() })
+    }
+
+    // Add freshly generated one-time pre keys (see User::generate_opks) to a registered user
+    pub fn upload_opks(&mut self, user_name: &str, keys: Vec<(u32, PublicKey)>) -> Result<(), KeyServerError> {
+        let bundle = self.bundles.get_mut(user_name).ok_or(KeyServerError::UnknownUser)?;
+        bundle.opks_p.extend(keys);
+        Ok(())
+    }
+
+    // number of one-time pre keys the server still holds for a user
+    pub fn opk_count(&self, user_name: &str) -> Result<usize, KeyServerError> {
+        self.bundles
+            .get(user_name)
+            .map(|bundle| bundle.opks_p.len())
+            .ok_or(KeyServerError::UnknownUser)
+    }
+}
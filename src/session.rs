@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use x25519_dalek::PublicKey;
+
+use crate::crypto::aead::{decrypt_message, encrypt_message, AeadError};
+use crate::keys::KeyPair;
+use crate::ratchet::{ChainKey, MessageKeys, RootKey};
+
+// default for how many messages a single incoming message may skip over in its chain
+pub const MAX_SKIP: u32 = 1000;
+// default for how many skipped message keys a session keeps, the oldest are evicted first.
+// decrypt never copies this cache, keys are only added to it once a message has verified
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+// A message produced by Session::encrypt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RatchetMessage {
+    pub ratchet_key: PublicKey, //sender's current ratchet public key
+    pub counter: u32, //index of this message in the sending chain
+    pub previous_counter: u32, //length of the sender's previous sending chain
+    pub ciphertext: Vec<u8> //AES-256-CBC ciphertext followed by its HMAC-SHA256 tag
+}
+
+impl RatchetMessage {
+    // the header fields, authenticated together with the ciphertext
+    fn header(&self) -> Vec<u8> {
+        let mut header: Vec<u8> = Vec::with_capacity(32 + 4 + 4);
+        header.extend_from_slice(self.ratchet_key.as_bytes());
+        header.extend_from_slice(&self.counter.to_be_bytes());
+        header.extend_from_slice(&self.previous_counter.to_be_bytes());
+        header
+    }
+}
+
+// Reasons a session can not encrypt or decrypt a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    NoSendingChain, //the responder has to receive a message before it can send
+    DuplicateMessage, //the message's keys were already used or have been evicted
+    TooManySkipped, //the message is further ahead in its chain than the skip limit allows
+    InvalidMac, //the tag does not match, the message was altered or keys differ
+    InvalidCiphertext //the tag matched but the ciphertext does not decrypt
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::NoSendingChain => write!(f, "session can not send before receiving"),
+            SessionError::DuplicateMessage => write!(f, "message was already received or its keys were evicted"),
+            SessionError::TooManySkipped => write!(f, "message skips too many messages"),
+            SessionError::InvalidMac => write!(f, "MAC verification failed"),
+            SessionError::InvalidCiphertext => write!(f, "ciphertext could not be decrypted"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<AeadError> for SessionError {
+    fn from(err: AeadError) -> SessionError {
+        match err {
+            AeadError::InvalidMac => SessionError::InvalidMac,
+            AeadError::InvalidCiphertext => SessionError::InvalidCiphertext,
+        }
+    }
+}
+
+// Double Ratchet state between two users, set up from an X3DH shared secret
+#[derive(Clone)]
+pub struct Session {
+    associated_data: [u8; 64], //initiator's identity key then responder's, authenticated with every message
+    state: RatchetState,
+    skipped_keys: VecDeque<(PublicKey, MessageKeys)>, //keys of messages not received yet, oldest first
+    max_skip: u32,
+    max_skipped_keys: usize
+}
+
+// The keys and counters a decrypt advances. Small enough to copy, so a decrypt can work on a
+// copy and commit it only once the message has verified.
+#[derive(Clone)]
+struct RatchetState {
+    root_key: RootKey,
+    our_ratchet_key: KeyPair,
+    their_ratchet_key: Option<PublicKey>,
+    sending_chain: Option<ChainKey>,
+    receiving_chain: Option<ChainKey>,
+    previous_counter: u32
+}
+
+impl Session {
+    // Session for the X3DH initiator, the responder's signed pre key is their first ratchet key
+    pub fn initiate(shared_secret: [u8; 32], our_identity_key: PublicKey, their_identity_key: PublicKey, their_ratchet_key: PublicKey) -> Session {
+        let our_ratchet_key: KeyPair = KeyPair::generate();
+        let (root_key, sending_chain) = RootKey::new(shared_secret).create_chain(&their_ratchet_key, &our_ratchet_key);
+
+        Session::new(our_identity_key, their_identity_key, RatchetState {
+            root_key,
+            our_ratchet_key,
+            their_ratchet_key: Some(their_ratchet_key),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            previous_counter: 0
+        })
+    }
+
+    // Session for the X3DH responder, whose signed pre key is its first ratchet key
+    pub fn respond(shared_secret: [u8; 32], our_identity_key: PublicKey, their_identity_key: PublicKey, our_ratchet_key: KeyPair) -> Session {
+        Session::new(their_identity_key, our_identity_key, RatchetState {
+            root_key: RootKey::new(shared_secret),
+            our_ratchet_key,
+            their_ratchet_key: None,
+            sending_chain: None,
+            receiving_chain: None,
+            previous_counter: 0
+        })
+    }
+
+    // As in X3DH the associated data is the initiator's identity key followed by the responder's,
+    // so a session only accepts messages from a session between the same two identities
+    fn new(initiator_identity_key: PublicKey, responder_identity_key: PublicKey, state: RatchetState) -> Session {
+        let mut associated_data = [0u8; 64];
+        associated_data[..32].copy_from_slice(initiator_identity_key.as_bytes());
+        associated_data[32..].copy_from_slice(responder_identity_key.as_bytes());
+
+        Session {
+            associated_data,
+            state,
+            skipped_keys: VecDeque::new(),
+            max_skip: MAX_SKIP,
+            max_skipped_keys: MAX_SKIPPED_KEYS
+        }
+    }
+
+    // Change how far a message may jump ahead in its chain and how many skipped keys are kept
+    pub fn set_skip_limits(&mut self, max_skip: u32, max_skipped_keys: usize) {
+        self.max_skip = max_skip;
+        self.max_skipped_keys = max_skipped_keys;
+        self.evict_skipped_keys();
+    }
+
+    // number of stored keys for messages that were skipped and not received yet
+    pub fn skipped_key_count(&self) -> usize {
+        self.skipped_keys.len()
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<RatchetMessage, SessionError> {
+        let sending_chain = self.state.sending_chain.as_ref().ok_or(SessionError::NoSendingChain)?;
+        let keys: MessageKeys = sending_chain.message_keys();
+
+        let mut message = RatchetMessage {
+            ratchet_key: self.state.our_ratchet_key.public,
+            counter: keys.counter,
+            previous_counter: self.state.previous_counter,
+            ciphertext: Vec::new()
+        };
+        message.ciphertext = encrypt_message(&keys, plaintext, &self.associated_data(&message));
+
+        self.state.sending_chain = Some(sending_chain.next_chain_key());
+        Ok(message)
+    }
+
+    // Decrypt a message from the peer. A failed decrypt leaves the session unchanged: the ratchet
+    // state is advanced on a copy and newly skipped keys are collected aside, both are committed
+    // only after the MAC has verified.
+    pub fn decrypt(&mut self, message: &RatchetMessage) -> Result<Vec<u8>, SessionError> {
+        // a message that was skipped earlier uses its stored keys
+        if let Some(position) = self.skipped_keys.iter()
+            .position(|(ratchet_key, keys)| *ratchet_key == message.ratchet_key && keys.counter == message.counter)
+        {
+            let plaintext: Vec<u8> = decrypt_message(&self.skipped_keys[position].1, &message.ciphertext, &self.associated_data(message))?;
+            self.skipped_keys.remove(position);
+            return Ok(plaintext);
+        }
+
+        let mut state: RatchetState = self.state.clone();
+        let mut skipped: Vec<(PublicKey, MessageKeys)> = Vec::new();
+        if state.their_ratchet_key != Some(message.ratchet_key) {
+            // keep the keys of messages still in flight on the peer's previous chain
+            state.skip_message_keys(message.previous_counter, self.max_skip, &mut skipped)?;
+            state.ratchet_step(message.ratchet_key);
+        }
+        state.skip_message_keys(message.counter, self.max_skip, &mut skipped)?;
+
+        let receiving_chain = state.receiving_chain.as_ref().expect("set by the ratchet step");
+        if message.counter < receiving_chain.index() {
+            return Err(SessionError::DuplicateMessage);
+        }
+        let keys: MessageKeys = receiving_chain.message_keys();
+        let next_chain: ChainKey = receiving_chain.next_chain_key();
+        let plaintext: Vec<u8> = decrypt_message(&keys, &message.ciphertext, &self.associated_data(message))?;
+
+        state.receiving_chain = Some(next_chain);
+        self.state = state;
+        self.skipped_keys.extend(skipped);
+        self.evict_skipped_keys();
+        Ok(plaintext)
+    }
+
+    // the MAC input besides the ciphertext: the session's associated data followed by the message header
+    fn associated_data(&self, message: &RatchetMessage) -> Vec<u8> {
+        [&self.associated_data[..], &message.header()].concat()
+    }
+
+    fn evict_skipped_keys(&mut self) {
+        while self.skipped_keys.len() > self.max_skipped_keys {
+            self.skipped_keys.pop_front();
+        }
+    }
+}
+
+impl RatchetState {
+    // Step the receiving chain up to counter, collecting the keys of the messages passed over
+    fn skip_message_keys(&mut self, counter: u32, max_skip: u32, skipped: &mut Vec<(PublicKey, MessageKeys)>) -> Result<(), SessionError> {
+        let (their_ratchet_key, mut receiving_chain) = match (self.their_ratchet_key, self.receiving_chain.take()) {
+            (Some(their_ratchet_key), Some(receiving_chain)) => (their_ratchet_key, receiving_chain),
+            _ => return Ok(()), //nothing received yet, so nothing to skip
+        };
+        if counter.saturating_sub(receiving_chain.index()) > max_skip {
+            return Err(SessionError::TooManySkipped);
+        }
+
+        while receiving_chain.index() < counter {
+            skipped.push((their_ratchet_key, receiving_chain.message_keys()));
+            receiving_chain = receiving_chain.next_chain_key();
+        }
+        self.receiving_chain = Some(receiving_chain);
+        Ok(())
+    }
+
+    // The peer moved to a new ratchet key: derive their new sending chain, then our own with a fresh key
+    fn ratchet_step(&mut self, their_ratchet_key: PublicKey) {
+        let (root_key, receiving_chain) = self.root_key.create_chain(&their_ratchet_key, &self.our_ratchet_key);
+        let our_ratchet_key: KeyPair = KeyPair::generate();
+        let (root_key, sending_chain) = root_key.create_chain(&their_ratchet_key, &our_ratchet_key);
+
+        self.previous_counter = self.sending_chain.as_ref().map_or(0, ChainKey::index);
+        self.root_key = root_key;
+        self.our_ratchet_key = our_ratchet_key;
+        self.their_ratchet_key = Some(their_ratchet_key);
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_chain = Some(sending_chain);
+    }
+}
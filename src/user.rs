@@ -1,28 +1,83 @@
 use rand::{Rng, rngs::OsRng};
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use x25519_dalek::PublicKey;
 use ed25519_dalek::{SigningKey, Signature, Signer};
-use std::collections::HashMap;
+use std::collections::{btree_map::Entry, BTreeMap, HashMap};
+use std::fmt;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::bundle::{BundleError, KeyBundle};
+use crate::keys::KeyPair;
+
+// device registration ids are 14 bits wide, matching the range Signal clients generate.
+// A User is a single device and the account is its name, so one id per User identifies the installation
+const MAX_DEVICE_REGISTRATION_ID: u32 = 16380;
+
+// application specific info string bound into the X3DH KDF output
+const X3DH_INFO: &[u8] = b"PQ_Signal_X3DH";
 
 // a user structure that holds the private and public keys, the signature, and other related fields.
 pub struct User{
     pub name: String,
-    pub ik_s: EphemeralSecret, //private_identity_key
-    pub ik_p: PublicKey, //public_identity_key
-    pub spk_s: EphemeralSecret, //private_signed_pre_key
-    pub spk_p: PublicKey, //public_signed_pre_key
+    pub device_registration_id: u32, //random id of this installation, a new User gets a new one
+    pub ik: KeyPair, //identity_key
+    pub signing_key: SigningKey, //identity signing key, used to sign the pre key
+    pub spk: KeyPair, //signed_pre_key
     pub spk_sig: Signature, //signed_pre_key_signature
-    pub opks_s: Vec<(EphemeralSecret, PublicKey)>, //one-time pre keys (public and private) 
-    pub opks_p: Vec<PublicKey>, //one-time pre keys (public only "published")
+    pub opks: BTreeMap<u32, KeyPair>, //one-time pre keys by id, the public halves are published
+    pub next_opk_id: u32, //id given to the next generated one-time pre key
     pub key_bundles: HashMap<String, Vec<u8>>, //for serialised key bundles (public keys)
-    pub dr_keys: HashMap<String, Vec<u8>> //for derived keys used to encrypt or decrypt messages
+    pub dr_keys: HashMap<String, Vec<u8>>, //for derived keys used to encrypt or decrypt messages
+    pub peer_device_registration_ids: HashMap<String, u32> //device registration id of each user a handshake was completed with
+}
+
+// The bundle a user publishes, the same type is sent over the wire and stored in key_bundles
+pub type UserBundle = KeyBundle;
+
+// The first message of a session, sent by the initiator so the responder can run the same DHs
+#[derive(Debug, Clone, Copy)]
+pub struct InitialMessage {
+    pub device_registration_id: u32, //initiator's device registration id
+    pub ik_p: PublicKey, //initiator's public identity key
+    pub ek_p: PublicKey, //initiator's public ephemeral key
+    pub opk_id: Option<u32> //id of the responder's one-time pre key that was used, if any
+}
+
+// Implement HKDF using hkdf crate
+// As in the X3DH spec the input is prefixed with 32 0xFF bytes and the salt is all zeros
+fn x3dh_kdf(key_material: &[u8]) -> [u8; 32] {
+    let ikm = [&[0xFFu8; 32][..], key_material].concat();
+    let hkdf = Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm);
+    let mut output = [0u8; 32];
+    hkdf.expand(X3DH_INFO, &mut output).expect("HKDF expand error");
+    output
+}
+
+// Reasons an X3DH handshake can not be completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    InvalidSignature, //spk_sig does not verify over spk_p with the bundle's signing key
+    UnknownOneTimePreKey //the initial message names a one-time pre key this user does not hold
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::InvalidSignature => write!(f, "unable to verify signed pre key"),
+            HandshakeError::UnknownOneTimePreKey => write!(f, "unknown one-time pre key"),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct UserBundle {
-    pub ik_p: PublicKey,
-    pub spk_p: PublicKey,
-    pub spk_sig: Signature,
-    pub opks_p: Vec<PublicKey>
+impl std::error::Error for HandshakeError {}
+
+// How the peer's device registration id in a completed handshake compares with the last one seen for that user.
+// The id is not authenticated by X3DH, so it is only a hint for the caller to replace an old session, never a reason to refuse one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerRegistration {
+    New, //no id was known for this user yet
+    Unchanged, //same id as the last handshake or the saved bundle
+    Reregistered { previous: u32 } //the peer installed again, sessions with its previous installation should be replaced
 }
 
 // user implementation
@@ -30,58 +85,150 @@ impl User{
     //A "new" function, a constructor for creating a new User instance It takes two parameters and returns a new user instance
     pub fn new(name: String, max_opk_num: usize) -> User {
         let mut csprng: OsRng = OsRng; // Instance of CSPRNG (cryptographically secure pseudo random number generator)
-        let ik_s: EphemeralSecret = EphemeralSecret::random_from_rng(&mut csprng);
-        let ik_p: PublicKey = PublicKey::from(&ik_s); // Derives the public key from the private key
-        let spk_s: EphemeralSecret = EphemeralSecret::random_from_rng(&mut csprng);
-        let spk_p: PublicKey = PublicKey::from(&spk_s);
+        let device_registration_id: u32 = csprng.gen_range(1..=MAX_DEVICE_REGISTRATION_ID); // 14-bit id, 0 is reserved
+        let ik: KeyPair = KeyPair::generate();
+        let spk: KeyPair = KeyPair::generate();
 
-        //creating and signing the public pre key. need more explaination
+        // the signing key is kept so peers can verify the pre key against its public half
         let signing_key: SigningKey = SigningKey::from_bytes(&csprng.gen()); // Generate a new signing key from random bytes
-        let spk_sig: Signature = signing_key.sign(spk_p.as_bytes());
-
-        // set the capacity for the one-time pre keys to the max number specified
-        let mut opks_s: Vec<(EphemeralSecret, PublicKey)> = Vec::with_capacity(max_opk_num);
-        let mut opks_p: Vec<PublicKey> = Vec::with_capacity(max_opk_num);
-        
-        for _ in 0..max_opk_num{
-            let sk: EphemeralSecret = EphemeralSecret::random_from_rng(&mut csprng);
-            let pk: PublicKey = PublicKey::from(&sk);
-            opks_p.push(pk);
-            opks_s.push((sk, pk));
-        }
+        let spk_sig: Signature = signing_key.sign(spk.public.as_bytes());
 
-        User {
+        let mut user = User {
             name,
-            ik_s,
-            ik_p,
-            spk_s,
-            spk_p,
+            device_registration_id,
+            ik,
+            signing_key,
+            spk,
             spk_sig,
-            opks_s,
-            opks_p,
+            opks: BTreeMap::new(),
+            next_opk_id: 0,
             key_bundles: HashMap::new(),
-            dr_keys: HashMap::new()
-        }
+            dr_keys: HashMap::new(),
+            peer_device_registration_ids: HashMap::new()
+        };
+        user.generate_opks(max_opk_num);
+        user
     }
 
+    // Publish the public part of the user's key bundle
     pub fn publish(&self) -> UserBundle{
         UserBundle{
-            ik_p: self.ik_p,
-            spk_p: self.spk_p,
+            device_registration_id: self.device_registration_id,
+            ik_p: self.ik.public,
+            vk_p: self.signing_key.verifying_key(),
+            spk_p: self.spk.public,
             spk_sig: self.spk_sig,
-            opks_p: self.opks_p.clone()
+            opks_p: self.opks.iter().map(|(id, opk)| (*id, opk.public)).collect(),
         }
     }
 
-}
+    // Top up the one-time pre keys, returns the new public keys with their ids for publishing
+    // Ids wrap around after u32::MAX, ids of keys that are still unused are skipped so none is overwritten
+    pub fn generate_opks(&mut self, n: usize) -> Vec<(u32, PublicKey)> {
+        let free_ids: u64 = (1u64 << 32) - self.opks.len() as u64; // every id can be in use at most once
+        let n: usize = (n as u64).min(free_ids) as usize;
+        let mut published: Vec<(u32, PublicKey)> = Vec::with_capacity(n);
+        while published.len() < n {
+            let id: u32 = self.next_opk_id;
+            self.next_opk_id = self.next_opk_id.wrapping_add(1);
+            if let Entry::Vacant(entry) = self.opks.entry(id) {
+                let opk: &KeyPair = entry.insert(KeyPair::generate());
+                published.push((id, opk.public));
+            }
+        }
+        published
+    }
 
-fn main() {
-    let user: User = User::new("Alice".to_string(), 1);
+    // Remove a one-time pre key so it can never be used again, None if it is unknown or already used
+    pub fn take_opk(&mut self, id: u32) -> Option<KeyPair> {
+        self.opks.remove(&id)
+    }
 
-    let bundle: UserBundle = user.publish();
+    // number of one-time pre keys left, for deciding when to replenish
+    pub fn opk_count(&self) -> usize {
+        self.opks.len()
+    }
 
-    println!("{:?}", bundle);
-    
-    println!("hello world");
-    
-}
\ No newline at end of file
+    // Save another user's bundle in its serialised form
+    pub fn save_bundle(&mut self, user_name: &str, bundle: &KeyBundle) -> Result<(), BundleError> {
+        self.key_bundles.insert(user_name.to_string(), bundle.serialize()?);
+        Ok(())
+    }
+
+    // Load a bundle saved with save_bundle, None if there is none or it no longer parses
+    pub fn load_bundle(&self, user_name: &str) -> Option<KeyBundle> {
+        self.key_bundles.get(user_name).and_then(|bytes| KeyBundle::deserialize(bytes).ok())
+    }
+
+    // Record the peer's device registration id, comparing it with the last one from a handshake or a saved bundle.
+    // This is the only place ids are checked, sessions never see them
+    fn record_peer_registration(&mut self, user_name: &str, device_registration_id: u32) -> PeerRegistration {
+        let known_id: Option<u32> = self.peer_device_registration_ids.get(user_name).copied()
+            .or_else(|| self.load_bundle(user_name).map(|bundle| bundle.device_registration_id));
+        self.peer_device_registration_ids.insert(user_name.to_string(), device_registration_id);
+
+        match known_id {
+            None => PeerRegistration::New,
+            Some(id) if id == device_registration_id => PeerRegistration::Unchanged,
+            Some(previous) => PeerRegistration::Reregistered { previous },
+        }
+    }
+
+    // Perform an initial handshake with another user (X3DH initiator side)
+    // The derived secret is stored in dr_keys under user_name and the message for the responder is returned together with
+    // how the bundle's device registration id compares with the known one. Nothing is derived if the bundle's signed pre key does not verify
+    pub fn initial_handshake(&mut self, user_name: &str, bundle: &UserBundle) -> Result<(InitialMessage, PeerRegistration), HandshakeError> {
+        bundle.verify()?;
+
+        let ek: KeyPair = KeyPair::generate();
+        let opk: Option<(u32, PublicKey)> = bundle.opks_p.first().copied(); // DH4 is skipped when no one-time pre key is left
+
+        let dh_1 = self.ik.diffie_hellman(&bundle.spk_p);
+        let dh_2 = ek.diffie_hellman(&bundle.ik_p);
+        let dh_3 = ek.diffie_hellman(&bundle.spk_p);
+
+        // Concatenate DH results and derive the send secret key
+        let mut key_material: Vec<u8> = [
+            &dh_1.as_bytes()[..],
+            &dh_2.as_bytes()[..],
+            &dh_3.as_bytes()[..],
+        ]
+        .concat();
+        if let Some((_, opk_p)) = opk {
+            let dh_4 = ek.diffie_hellman(&opk_p);
+            key_material.extend_from_slice(dh_4.as_bytes());
+        }
+
+        let sk: [u8; 32] = x3dh_kdf(&key_material);
+        self.dr_keys.insert(user_name.to_string(), sk.to_vec());
+        let peer: PeerRegistration = self.record_peer_registration(user_name, bundle.device_registration_id);
+
+        let message = InitialMessage { device_registration_id: self.device_registration_id, ik_p: self.ik.public, ek_p: ek.public, opk_id: opk.map(|(id, _)| id) };
+        Ok((message, peer))
+    }
+
+    // Complete the handshake on the responder side by running the same DHs from the other end
+    // The one-time pre key used is consumed and the initiator's device registration id is compared with the known one,
+    // the same way as on the initiator side. Fails if the message refers to a one-time pre key this user does not hold
+    pub fn receive_handshake(&mut self, user_name: &str, message: &InitialMessage) -> Result<PeerRegistration, HandshakeError> {
+        let dh_1 = self.spk.diffie_hellman(&message.ik_p);
+        let dh_2 = self.ik.diffie_hellman(&message.ek_p);
+        let dh_3 = self.spk.diffie_hellman(&message.ek_p);
+
+        let mut key_material: Vec<u8> = [
+            &dh_1.as_bytes()[..],
+            &dh_2.as_bytes()[..],
+            &dh_3.as_bytes()[..],
+        ]
+        .concat();
+        if let Some(opk_id) = message.opk_id {
+            let opk: KeyPair = self.take_opk(opk_id).ok_or(HandshakeError::UnknownOneTimePreKey)?;
+            let dh_4 = opk.diffie_hellman(&message.ek_p);
+            key_material.extend_from_slice(dh_4.as_bytes());
+        }
+
+        let sk: [u8; 32] = x3dh_kdf(&key_material);
+        self.dr_keys.insert(user_name.to_string(), sk.to_vec());
+        Ok(self.record_peer_registration(user_name, message.device_registration_id))
+    }
+}
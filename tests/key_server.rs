@@ -0,0 +1,103 @@
+use pq_signal::{HandshakeError, KeyServer, KeyServerError, User, UserBundle};
+
+#[test]
+fn handshake_through_the_server() {
+    let mut server = KeyServer::new();
+    let mut alice = User::new("Alice".to_string(), 1);
+    let mut bob = User::new("Bob".to_string(), 2);
+    server.register("Bob", bob.publish()).unwrap();
+
+    let bundle = server.fetch_bundle("Bob").unwrap();
+    assert_eq!(bundle.opks_p.len(), 1);
+    assert_eq!(server.opk_count("Bob"), Ok(1));
+
+    let (message, _) = alice.initial_handshake("Bob", &bundle).unwrap();
+    bob.receive_handshake("Alice", &message).unwrap();
+    assert_eq!(alice.dr_keys["Bob"], bob.dr_keys["Alice"]);
+}
+
+#[test]
+fn each_fetch_hands_out_a_different_one_time_pre_key() {
+    let mut server = KeyServer::new();
+    let bob = User::new("Bob".to_string(), 2);
+    server.register("Bob", bob.publish()).unwrap();
+
+    let first = server.fetch_bundle("Bob").unwrap();
+    let second = server.fetch_bundle("Bob").unwrap();
+    assert_eq!(first.opks_p, vec![bob.publish().opks_p[0]]);
+    assert_eq!(second.opks_p, vec![bob.publish().opks_p[1]]);
+}
+
+#[test]
+fn exhausted_one_time_pre_keys_and_replenishment() {
+    let mut server = KeyServer::new();
+    let mut bob = User::new("Bob".to_string(), 1);
+    server.register("Bob", bob.publish()).unwrap();
+
+    // Alice takes the only one-time pre key
+    let mut alice = User::new("Alice".to_string(), 1);
+    let (message, _) = alice.initial_handshake("Bob", &server.fetch_bundle("Bob").unwrap()).unwrap();
+    bob.receive_handshake("Alice", &message).unwrap();
+    assert_eq!(server.opk_count("Bob"), Ok(0));
+
+    // Carol still gets a bundle, the handshake runs without DH4
+    let mut carol = User::new("Carol".to_string(), 1);
+    let bundle = server.fetch_bundle("Bob").unwrap();
+    assert!(bundle.opks_p.is_empty());
+    let (message, _) = carol.initial_handshake("Bob", &bundle).unwrap();
+    assert_eq!(message.opk_id, None);
+    bob.receive_handshake("Carol", &message).unwrap();
+    assert_eq!(carol.dr_keys["Bob"], bob.dr_keys["Carol"]);
+
+    // Bob tops up and the next fetch gets one of the new keys
+    server.upload_opks("Bob", bob.generate_opks(2)).unwrap();
+    assert_eq!(server.opk_count("Bob"), Ok(2));
+    let mut dave = User::new("Dave".to_string(), 1);
+    let (message, _) = dave.initial_handshake("Bob", &server.fetch_bundle("Bob").unwrap()).unwrap();
+    assert_eq!(message.opk_id, Some(1));
+    bob.receive_handshake("Dave", &message).unwrap();
+    assert_eq!(dave.dr_keys["Bob"], bob.dr_keys["Dave"]);
+}
+
+#[test]
+fn unknown_users_and_invalid_bundles_are_refused() {
+    let mut server = KeyServer::new();
+    assert_eq!(server.fetch_bundle("Bob").unwrap_err(), KeyServerError::UnknownUser);
+    assert_eq!(server.upload_opks("Bob", Vec::new()), Err(KeyServerError::UnknownUser));
+    assert_eq!(server.opk_count("Bob"), Err(KeyServerError::UnknownUser));
+
+    let bob = User::new("Bob".to_string(), 1);
+    let mallory = User::new("Mallory".to_string(), 1);
+    let forged = UserBundle { spk_p: mallory.spk.public, ..bob.publish() };
+    assert_eq!(
+        server.register("Bob", forged),
+        Err(KeyServerError::InvalidBundle(HandshakeError::InvalidSignature))
+    );
+    assert_eq!(server.fetch_bundle("Bob").unwrap_err(), KeyServerError::UnknownUser);
+}
+
+#[test]
+fn registering_again_does_not_hand_out_one_time_pre_keys_twice() {
+    let mut server = KeyServer::new();
+    let mut bob = User::new("Bob".to_string(), 2);
+    server.register("Bob", bob.publish()).unwrap();
+
+    // Alice fetches a bundle, then Bob re-registers before her initial message arrives
+    let mut alice = User::new("Alice".to_string(), 1);
+    let bundle = server.fetch_bundle("Bob").unwrap();
+    server.register("Bob", bob.publish()).unwrap();
+    assert_eq!(server.opk_count("Bob"), Ok(1));
+
+    let mut carol = User::new("Carol".to_string(), 1);
+    let (to_bob, _) = carol.initial_handshake("Bob", &server.fetch_bundle("Bob").unwrap()).unwrap();
+    let (from_alice, _) = alice.initial_handshake("Bob", &bundle).unwrap();
+    assert_ne!(to_bob.opk_id, from_alice.opk_id);
+    bob.receive_handshake("Alice", &from_alice).unwrap();
+    bob.receive_handshake("Carol", &to_bob).unwrap();
+    assert_eq!(carol.dr_keys["Bob"], bob.dr_keys["Carol"]);
+
+    // a new installation under the same name replaces the one-time pre keys with its own
+    let bob = User::new("Bob".to_string(), 3);
+    server.register("Bob", bob.publish()).unwrap();
+    assert_eq!(server.opk_count("Bob"), Ok(3));
+}
@@ -0,0 +1,141 @@
+use pq_signal::{Session, SessionError, User};
+
+// run X3DH between two fresh users and start a session on each side
+fn sessions() -> (Session, Session) {
+    let mut alice = User::new("Alice".to_string(), 1);
+    let mut bob = User::new("Bob".to_string(), 1);
+
+    let (message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    bob.receive_handshake("Alice", &message).unwrap();
+
+    let alice_sk: [u8; 32] = alice.dr_keys["Bob"].as_slice().try_into().unwrap();
+    let bob_sk: [u8; 32] = bob.dr_keys["Alice"].as_slice().try_into().unwrap();
+    (
+        Session::initiate(alice_sk, alice.ik.public, bob.ik.public, bob.spk.public),
+        Session::respond(bob_sk, bob.ik.public, alice.ik.public, bob.spk.clone()),
+    )
+}
+
+#[test]
+fn messages_flow_both_ways() {
+    let (mut alice, mut bob) = sessions();
+
+    let hello = alice.encrypt(b"hello bob").unwrap();
+    assert_eq!(bob.decrypt(&hello).unwrap(), b"hello bob");
+
+    let reply = bob.encrypt(b"hi alice").unwrap();
+    assert_ne!(reply.ratchet_key, hello.ratchet_key);
+    assert_eq!(alice.decrypt(&reply).unwrap(), b"hi alice");
+
+    // several messages in a row on one chain, then another ratchet step
+    for i in 0..3u32 {
+        let message = alice.encrypt(format!("message {}", i).as_bytes()).unwrap();
+        assert_eq!(message.counter, i);
+        assert_eq!(bob.decrypt(&message).unwrap(), format!("message {}", i).as_bytes());
+    }
+    let reply = bob.encrypt(b"").unwrap();
+    assert_eq!(reply.previous_counter, 1);
+    assert_eq!(alice.decrypt(&reply).unwrap(), b"");
+}
+
+#[test]
+fn responder_can_not_send_first() {
+    let (_, mut bob) = sessions();
+    assert_eq!(bob.encrypt(b"too early").unwrap_err(), SessionError::NoSendingChain);
+}
+
+#[test]
+fn tampered_message_is_rejected_without_changing_state() {
+    let (mut alice, mut bob) = sessions();
+    let message = alice.encrypt(b"attack at dawn").unwrap();
+
+    let mut tampered = message.clone();
+    tampered.ciphertext[0] ^= 1;
+    assert_eq!(bob.decrypt(&tampered), Err(SessionError::InvalidMac));
+
+    let mut tampered = message.clone();
+    tampered.previous_counter += 1;
+    assert_eq!(bob.decrypt(&tampered), Err(SessionError::InvalidMac));
+
+    assert_eq!(bob.decrypt(&message).unwrap(), b"attack at dawn");
+}
+
+#[test]
+fn out_of_order_messages_use_skipped_keys() {
+    let (mut alice, mut bob) = sessions();
+    let first = alice.encrypt(b"first").unwrap();
+    let second = alice.encrypt(b"second").unwrap();
+    let third = alice.encrypt(b"third").unwrap();
+
+    assert_eq!(bob.decrypt(&third).unwrap(), b"third");
+    assert_eq!(bob.skipped_key_count(), 2);
+    assert_eq!(bob.decrypt(&first).unwrap(), b"first");
+    assert_eq!(bob.decrypt(&second).unwrap(), b"second");
+    assert_eq!(bob.skipped_key_count(), 0);
+
+    // each key is used once
+    assert_eq!(bob.decrypt(&second), Err(SessionError::DuplicateMessage));
+    assert_eq!(bob.decrypt(&third), Err(SessionError::DuplicateMessage));
+}
+
+#[test]
+fn messages_from_a_previous_chain_arrive_after_a_ratchet_step() {
+    let (mut alice, mut bob) = sessions();
+    bob.decrypt(&alice.encrypt(b"hello").unwrap()).unwrap();
+    let late = alice.encrypt(b"late").unwrap();
+
+    // Bob replies and Alice moves to a new chain before her earlier message arrives
+    alice.decrypt(&bob.encrypt(b"reply").unwrap()).unwrap();
+    let next = alice.encrypt(b"next chain").unwrap();
+    assert_eq!(next.previous_counter, 2);
+
+    assert_eq!(bob.decrypt(&next).unwrap(), b"next chain");
+    assert_eq!(bob.skipped_key_count(), 1);
+    assert_eq!(bob.decrypt(&late).unwrap(), b"late");
+}
+
+#[test]
+fn skipping_is_bounded() {
+    let (mut alice, mut bob) = sessions();
+    bob.set_skip_limits(2, 3);
+
+    let messages: Vec<_> = (0..8u8).map(|i| alice.encrypt(&[i]).unwrap()).collect();
+
+    // too far ahead, and the failure leaves nothing behind
+    assert_eq!(bob.decrypt(&messages[3]), Err(SessionError::TooManySkipped));
+    assert_eq!(bob.skipped_key_count(), 0);
+
+    assert_eq!(bob.decrypt(&messages[2]).unwrap(), [2]);
+    assert_eq!(bob.decrypt(&messages[5]).unwrap(), [5]);
+    assert_eq!(bob.skipped_key_count(), 3);
+    assert_eq!(bob.decrypt(&messages[7]).unwrap(), [7]);
+
+    // only the three newest skipped keys are kept
+    assert_eq!(bob.skipped_key_count(), 3);
+    assert_eq!(bob.decrypt(&messages[0]), Err(SessionError::DuplicateMessage));
+    assert_eq!(bob.decrypt(&messages[1]), Err(SessionError::DuplicateMessage));
+    assert_eq!(bob.decrypt(&messages[3]).unwrap(), [3]);
+    assert_eq!(bob.decrypt(&messages[4]).unwrap(), [4]);
+    assert_eq!(bob.decrypt(&messages[6]).unwrap(), [6]);
+}
+
+#[test]
+fn session_is_bound_to_both_identity_keys() {
+    let mut alice = User::new("Alice".to_string(), 0);
+    let mut bob = User::new("Bob".to_string(), 0);
+    let mallory = User::new("Mallory".to_string(), 0);
+
+    let (message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    bob.receive_handshake("Alice", &message).unwrap();
+    let alice_sk: [u8; 32] = alice.dr_keys["Bob"].as_slice().try_into().unwrap();
+    let bob_sk: [u8; 32] = bob.dr_keys["Alice"].as_slice().try_into().unwrap();
+
+    // same shared secret, but Bob believes he is talking to Mallory
+    let mut alice_session = Session::initiate(alice_sk, alice.ik.public, bob.ik.public, bob.spk.public);
+    let mut bob_session = Session::respond(bob_sk, bob.ik.public, mallory.ik.public, bob.spk.clone());
+    let hello = alice_session.encrypt(b"hello bob").unwrap();
+    assert_eq!(bob_session.decrypt(&hello), Err(SessionError::InvalidMac));
+
+    let mut bob_session = Session::respond(bob_sk, bob.ik.public, alice.ik.public, bob.spk.clone());
+    assert_eq!(bob_session.decrypt(&hello).unwrap(), b"hello bob");
+}
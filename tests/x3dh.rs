@@ -0,0 +1,199 @@
+use pq_signal::{HandshakeError, KeyBundle, PeerRegistration, User, UserBundle};
+
+#[test]
+fn alice_and_bob_derive_the_same_secret() {
+    let mut alice = User::new("Alice".to_string(), 3);
+    let mut bob = User::new("Bob".to_string(), 3);
+
+    let (message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    assert_eq!(message.opk_id, Some(0));
+    bob.receive_handshake("Alice", &message).unwrap();
+
+    assert_eq!(alice.dr_keys["Bob"], bob.dr_keys["Alice"]);
+    assert_eq!(alice.dr_keys["Bob"].len(), 32);
+}
+
+#[test]
+fn handshake_without_one_time_pre_keys() {
+    let mut alice = User::new("Alice".to_string(), 0);
+    let mut bob = User::new("Bob".to_string(), 0);
+
+    let (message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    assert_eq!(message.opk_id, None);
+    bob.receive_handshake("Alice", &message).unwrap();
+
+    assert_eq!(alice.dr_keys["Bob"], bob.dr_keys["Alice"]);
+}
+
+#[test]
+fn published_bundle_verifies() {
+    let bob = User::new("Bob".to_string(), 1);
+    assert_eq!(bob.publish().verify(), Ok(()));
+
+    // a signature from some other signing key does not verify
+    let mallory = User::new("Mallory".to_string(), 1);
+    let bundle = UserBundle { vk_p: mallory.signing_key.verifying_key(), ..bob.publish() };
+    assert_eq!(bundle.verify(), Err(HandshakeError::InvalidSignature));
+}
+
+#[test]
+fn initiator_rejects_bundle_with_bad_signature() {
+    let mut alice = User::new("Alice".to_string(), 1);
+    let bob = User::new("Bob".to_string(), 1);
+    let mallory = User::new("Mallory".to_string(), 1);
+
+    // swap in Mallory's pre key while keeping Bob's signature
+    let bundle = UserBundle { spk_p: mallory.spk.public, ..bob.publish() };
+
+    assert_eq!(bundle.verify(), Err(HandshakeError::InvalidSignature));
+    assert_eq!(alice.initial_handshake("Bob", &bundle).unwrap_err(), HandshakeError::InvalidSignature);
+    assert!(!alice.dr_keys.contains_key("Bob"));
+}
+
+#[test]
+fn responder_rejects_unknown_one_time_pre_key() {
+    let mut alice = User::new("Alice".to_string(), 1);
+    let mut bob = User::new("Bob".to_string(), 1);
+
+    let (mut message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    message.opk_id = Some(7);
+
+    assert_eq!(bob.receive_handshake("Alice", &message), Err(HandshakeError::UnknownOneTimePreKey));
+    assert!(!bob.dr_keys.contains_key("Alice"));
+}
+
+#[test]
+fn identity_key_survives_consecutive_handshakes() {
+    let mut alice = User::new("Alice".to_string(), 2);
+    let mut bob = User::new("Bob".to_string(), 2);
+    let mut carol = User::new("Carol".to_string(), 2);
+
+    // Alice initiates twice with the same identity key
+    let (to_bob, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    let (to_carol, _) = alice.initial_handshake("Carol", &carol.publish()).unwrap();
+    bob.receive_handshake("Alice", &to_bob).unwrap();
+    carol.receive_handshake("Alice", &to_carol).unwrap();
+    assert_eq!(alice.dr_keys["Bob"], bob.dr_keys["Alice"]);
+    assert_eq!(alice.dr_keys["Carol"], carol.dr_keys["Alice"]);
+
+    // Bob responds twice with the same identity and signed pre key
+    let (from_carol, _) = carol.initial_handshake("Bob", &bob.publish()).unwrap();
+    bob.receive_handshake("Carol", &from_carol).unwrap();
+    assert_eq!(carol.dr_keys["Bob"], bob.dr_keys["Carol"]);
+
+    // a second handshake between the same pair gives a fresh secret
+    let previous = alice.dr_keys["Bob"].clone();
+    let (again, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    bob.receive_handshake("Alice", &again).unwrap();
+    assert_eq!(alice.dr_keys["Bob"], bob.dr_keys["Alice"]);
+    assert_ne!(alice.dr_keys["Bob"], previous);
+}
+
+#[test]
+fn handshake_over_a_transmitted_bundle() {
+    let mut alice = User::new("Alice".to_string(), 1);
+    let mut bob = User::new("Bob".to_string(), 1);
+
+    // Bob's bundle travels as bytes and Alice keeps it in key_bundles
+    let bytes = bob.publish().serialize().unwrap();
+    alice.save_bundle("Bob", &KeyBundle::deserialize(&bytes).unwrap()).unwrap();
+    assert_eq!(alice.key_bundles["Bob"], bytes);
+
+    let bundle = alice.load_bundle("Bob").unwrap();
+    let (message, _) = alice.initial_handshake("Bob", &bundle).unwrap();
+    bob.receive_handshake("Alice", &message).unwrap();
+    assert_eq!(alice.dr_keys["Bob"], bob.dr_keys["Alice"]);
+}
+
+#[test]
+fn one_time_pre_keys_are_consumed_and_replenished() {
+    let mut alice = User::new("Alice".to_string(), 1);
+    let mut bob = User::new("Bob".to_string(), 2);
+    assert_eq!(bob.opk_count(), 2);
+
+    let (message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    assert_eq!(message.opk_id, Some(0));
+    bob.receive_handshake("Alice", &message).unwrap();
+
+    // the private half is gone, so the same initial message can not be replayed
+    assert_eq!(bob.opk_count(), 1);
+    assert!(bob.take_opk(0).is_none());
+    assert_eq!(bob.receive_handshake("Alice", &message), Err(HandshakeError::UnknownOneTimePreKey));
+    assert_eq!(bob.publish().opks_p.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![1]);
+
+    // new keys continue the id sequence
+    let published = bob.generate_opks(3);
+    assert_eq!(published.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![2, 3, 4]);
+    assert_eq!(bob.opk_count(), 4);
+    assert_eq!(bob.take_opk(3).unwrap().public, published[1].1);
+    assert_eq!(bob.opk_count(), 3);
+}
+
+#[test]
+fn wrapped_one_time_pre_key_ids_skip_unused_keys() {
+    let mut bob = User::new("Bob".to_string(), 1);
+    let held = bob.publish().opks_p[0];
+    assert_eq!(held.0, 0);
+
+    // the id counter wraps around while key 0 is still unused
+    bob.next_opk_id = u32::MAX;
+    let published = bob.generate_opks(2);
+    assert_eq!(published.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![u32::MAX, 1]);
+    assert_eq!(bob.opk_count(), 3);
+    assert_eq!(bob.take_opk(0).unwrap().public, held.1);
+}
+
+#[test]
+fn handshake_records_device_registration_ids() {
+    let mut alice = User::new("Alice".to_string(), 1);
+    let mut bob = User::new("Bob".to_string(), 1);
+
+    let (message, peer) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    assert_eq!(peer, PeerRegistration::New);
+    assert_eq!(message.device_registration_id, alice.device_registration_id);
+    assert_eq!(bob.receive_handshake("Alice", &message), Ok(PeerRegistration::New));
+
+    assert_eq!(alice.peer_device_registration_ids["Bob"], bob.device_registration_id);
+    assert_eq!(bob.peer_device_registration_ids["Alice"], alice.device_registration_id);
+
+    // the other direction finds the ids it already knows
+    let (message, peer) = bob.initial_handshake("Alice", &alice.publish()).unwrap();
+    assert_eq!(peer, PeerRegistration::Unchanged);
+    assert_eq!(alice.receive_handshake("Bob", &message), Ok(PeerRegistration::Unchanged));
+}
+
+#[test]
+fn reinstalled_peer_is_reported_as_reregistered() {
+    let mut alice = User::new("Alice".to_string(), 1);
+    let mut bob = User::new("Bob".to_string(), 2);
+    let (message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    bob.receive_handshake("Alice", &message).unwrap();
+    let previous = alice.device_registration_id;
+
+    // Alice installs again with fresh keys and a new id, Bob accepts the handshake and reports the change
+    let mut alice = User::new("Alice".to_string(), 1);
+    alice.device_registration_id = previous % 16380 + 1;
+    let (message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    assert_eq!(bob.receive_handshake("Alice", &message), Ok(PeerRegistration::Reregistered { previous }));
+    assert_eq!(alice.dr_keys["Bob"], bob.dr_keys["Alice"]);
+    assert_eq!(bob.peer_device_registration_ids["Alice"], alice.device_registration_id);
+
+    // the initiator side applies the same rule to a bundle with a new id
+    let mut carol = User::new("Carol".to_string(), 0);
+    let mut old_alice = User::new("Alice".to_string(), 0);
+    old_alice.device_registration_id = previous;
+    carol.save_bundle("Alice", &old_alice.publish()).unwrap();
+    let (_, peer) = carol.initial_handshake("Alice", &alice.publish()).unwrap();
+    assert_eq!(peer, PeerRegistration::Reregistered { previous });
+}
+
+#[test]
+fn saved_bundle_counts_as_known_registration() {
+    let mut alice = User::new("Alice".to_string(), 1);
+    let mut bob = User::new("Bob".to_string(), 1);
+    bob.save_bundle("Alice", &alice.publish()).unwrap();
+
+    let (message, _) = alice.initial_handshake("Bob", &bob.publish()).unwrap();
+    assert_eq!(bob.receive_handshake("Alice", &message), Ok(PeerRegistration::Unchanged));
+    assert_eq!(alice.dr_keys["Bob"], bob.dr_keys["Alice"]);
+}